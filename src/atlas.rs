@@ -1,4 +1,5 @@
 use serde::Serialize;
+use serde_json::Value;
 use std::collections::HashMap;
 use std::cmp::Reverse;
 
@@ -8,63 +9,94 @@ pub struct Atlas {
     pub size: Size,
 }
 
-#[derive(Debug, Serialize, Copy, Clone)]
+/// Occupancy summary for a single page: how much of its area ended up
+/// covered by placed sprites versus left empty.
+#[derive(Debug, Clone, Copy)]
+pub struct UsedSpace {
+    pub used_area: u64,
+    pub total_area: u64,
+}
+
+impl UsedSpace {
+    pub fn occupancy(&self) -> f64 {
+        if self.total_area == 0 {
+            0.0
+        } else {
+            self.used_area as f64 / self.total_area as f64
+        }
+    }
+}
+
+/// Diagnostic detail for a single sprite that could not be placed, so a
+/// caller can report something actionable instead of a bare panic.
+#[derive(Debug, Clone)]
+pub struct PlacementFailure {
+    pub name: String,
+    pub size: Size,
+    pub largest_free_rect: Option<Size>,
+}
+
+/// Result of a single [`AtlasBuilder::build`] call: what still didn't fit,
+/// and why.
+#[derive(Debug)]
+pub struct BuildReport {
+    pub overflow: Vec<(String, Size, Option<Value>)>,
+    pub used_space: UsedSpace,
+    pub failures: Vec<PlacementFailure>,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
 pub struct Rect {
     pub left: u32,
     pub top: u32,
     #[serde(flatten)]
     pub size: Size,
+    /// Whether the source image was rotated 90° to fill this rect. `size`
+    /// always describes the footprint as placed in the atlas, so a rotated
+    /// entry has its source width/height swapped relative to `size`.
+    pub rotated: bool,
+    /// Index of the output texture page this rect was placed on. A single
+    /// `AtlasBuilder` only ever fills in `0`; multi-page packing is
+    /// orchestrated by the caller, which overwrites this once a rect's page
+    /// is known.
+    pub page: u32,
+    /// Normalized texture coordinates of this rect within its page, so
+    /// shaders can sample directly without dividing by the page size
+    /// themselves. Filled in by the caller once a page's final size is known.
+    pub u0: f32,
+    pub v0: f32,
+    pub u1: f32,
+    pub v1: f32,
+    /// Arbitrary caller-supplied metadata echoed back from the matching
+    /// `ImageDefinition`, e.g. a sprite name, animation frame index, or
+    /// nine-slice border.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<Value>,
 }
 
 impl Rect {
-    /// Insert rectangle of `size` into top left corner of rect. Remaning space
-    /// being split again into two rectangles, and smaller one returned
-    ///
-    /// +--------+           +--------+
-    /// |        |           | r | n  |
-    /// |        |   +---+   +---+----|
-    /// |   c    | + | r | = |        |
-    /// |        |   +---+   |   c    |
-    /// |        |           |        |
-    /// |        |           |        |
-    /// +--------+           +--------+
-    ///
-    fn insert(&mut self, size: Size) -> Option<(Rect, Option<Rect>)> {
-        if self.size == size {
-            info!("Inserted image has same size as target rect. Results in empty space of size 0");
-        }
-        if size.fit_in(self.size) {
-            let r = Rect {
-                left: self.left,
-                top: self.top,
-                size,
-            };
-            let other = if size.width == self.size.width {
-                self.size.height -= size.height;
-                self.top += size.height;
-                None
-            } else if size.height == self.size.height {
-                self.size.width -= size.width;
-                self.left += size.width;
-                None
-            } else {
-                // TODO: Experiment with horizontal vs vertical splitting
-                let rect = Rect {
-                    left: self.left + size.width,
-                    top: self.top,
-                    size: Size {
-                        width: self.size.width - size.width,
-                        height: size.height,
-                    },
-                };
-                self.size.height -= size.height;
-                self.top += size.height;
-                Some(rect)
-            };
-            Some((r, other))
-        } else {
-            None
-        }
+    fn right(&self) -> u32 {
+        self.left + self.size.width
+    }
+
+    fn bottom(&self) -> u32 {
+        self.top + self.size.height
+    }
+
+    /// Whether `self` fully contains `other`.
+    fn contains(&self, other: &Rect) -> bool {
+        other.left >= self.left
+            && other.top >= self.top
+            && other.right() <= self.right()
+            && other.bottom() <= self.bottom()
+    }
+
+    /// Whether `self` and `other` overlap over a non empty area.
+    fn intersects(&self, other: &Rect) -> bool {
+        self.left < other.right()
+            && self.right() > other.left
+            && self.top < other.bottom()
+            && self.bottom() > other.top
     }
 
     fn bound_size(&self) -> Size {
@@ -73,9 +105,18 @@ impl Rect {
             height: self.top + self.size.height,
         }
     }
+
+    /// Fill in `u0, v0, u1, v1` as normalized coordinates of this rect
+    /// against a page of `page_size`.
+    pub fn set_uv(&mut self, page_size: Size) {
+        self.u0 = self.left as f32 / page_size.width as f32;
+        self.v0 = self.top as f32 / page_size.height as f32;
+        self.u1 = self.right() as f32 / page_size.width as f32;
+        self.v1 = self.bottom() as f32 / page_size.height as f32;
+    }
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Serialize, Eq, PartialEq, Default)]
 pub struct Size {
     pub width: u32,
     pub height: u32,
@@ -99,26 +140,71 @@ impl Size {
             height: self.height.max(other.height),
         }
     }
+
+    fn rotated(self) -> Size {
+        Self {
+            width: self.height,
+            height: self.width,
+        }
+    }
+}
+
+/// Best-short-side-fit score of placing `size` into `free`, assuming it already fits.
+/// Lower is better; ties broken by the long side, also lower is better.
+fn short_side_fit_score(free: Size, size: Size) -> (u32, u32) {
+    let short_side = (free.width - size.width).min(free.height - size.height);
+    let long_side = (free.width - size.width).max(free.height - size.height);
+    (short_side, long_side)
 }
 
-/// Pack non repeating images into texture atlas of fixed size.
+/// Pack images into texture atlas of fixed size using the
+/// MaxRects best-short-side-fit strategy: every free rectangle is considered
+/// as a placement candidate (rather than only ever splitting the first space
+/// found), and placing a rect can carve slices out of every free rectangle it
+/// overlaps, not just the one it was placed into. With [`AtlasBuilder::allow_rotation`]
+/// enabled, each image is also tried rotated 90° and the better-fitting
+/// orientation is kept.
 pub struct AtlasBuilder {
-    empty_spaces: Vec<Rect>,
+    free_rects: Vec<Rect>,
     textures: HashMap<String, Rect>,
+    allow_rotation: bool,
+    border: u32,
+    width: u32,
+    height: u32,
 }
 
 impl AtlasBuilder {
     pub fn new(width: u32, height: u32) -> Self {
         Self {
-            empty_spaces: vec![Rect {
+            free_rects: vec![Rect {
                 left: 0,
                 top: 0,
                 size: Size { width, height },
+                ..Default::default()
             }],
             textures: HashMap::new(),
+            allow_rotation: false,
+            border: 0,
+            width,
+            height,
         }
     }
 
+    /// Opt-in to also trying each image rotated 90° and keeping whichever
+    /// orientation yields the better best-short-side-fit score.
+    pub fn allow_rotation(mut self, allow_rotation: bool) -> Self {
+        self.allow_rotation = allow_rotation;
+        self
+    }
+
+    /// Padding baked into every rect's footprint (see `Config::border`),
+    /// subtracted back out in `used_space` so occupancy reflects true
+    /// sprite coverage rather than the padded footprint.
+    pub fn border(mut self, border: u32) -> Self {
+        self.border = border;
+        self
+    }
+
     pub fn get_map(self) -> Atlas{
         let size = self.min_bounding_rect();
         Atlas{
@@ -127,31 +213,185 @@ impl AtlasBuilder {
         }
     }
 
-    pub fn build<T>(&mut self, images: T) -> Option<()>
+    /// Pack as many `images` as fit, returning those that did not fit (so the
+    /// caller can continue packing them onto another page) alongside
+    /// occupancy and failure diagnostics.
+    pub fn build<T>(&mut self, images: T) -> BuildReport
     where
-        T: IntoIterator<Item = (String, Size)>,
+        T: IntoIterator<Item = (String, Size, Option<Value>)>,
     {
         let mut data: Vec<_> = images.into_iter().collect();
-        data.sort_by_key(|(_name, size)| Reverse(size.height * size.width));
-        for (name, size) in data {
-            self.add_rect(name, size)?;
+        data.sort_by_key(|(_name, size, _tag)| Reverse(size.height * size.width));
+        let mut overflow = Vec::new();
+        let mut failures = Vec::new();
+        for (name, size, tag) in data {
+            if self.add_rect(name.clone(), size, tag.clone()).is_none() {
+                failures.push(PlacementFailure {
+                    name: name.clone(),
+                    size,
+                    largest_free_rect: self.largest_free_rect(),
+                });
+                overflow.push((name, size, tag));
+            }
+        }
+        BuildReport {
+            overflow,
+            used_space: self.used_space(),
+            failures,
+        }
+    }
+
+    fn largest_free_rect(&self) -> Option<Size> {
+        self.free_rects
+            .iter()
+            .map(|free| free.size)
+            .max_by_key(|size| size.width as u64 * size.height as u64)
+    }
+
+    pub fn used_space(&self) -> UsedSpace {
+        let used_area = self
+            .textures
+            .values()
+            .map(|rect| {
+                let width = rect.size.width.saturating_sub(self.border);
+                let height = rect.size.height.saturating_sub(self.border);
+                width as u64 * height as u64
+            })
+            .sum();
+        UsedSpace {
+            used_area,
+            total_area: self.width as u64 * self.height as u64,
+        }
+    }
+
+    /// Find the free rect that fits `size` with the smallest (short_side, long_side)
+    /// leftover, per the best-short-side-fit heuristic.
+    fn best_free_rect(&self, size: Size) -> Option<(usize, (u32, u32))> {
+        self.free_rects
+            .iter()
+            .enumerate()
+            .filter(|(_, free)| size.fit_in(free.size))
+            .map(|(i, free)| (i, short_side_fit_score(free.size, size)))
+            .min_by_key(|&(_, score)| score)
+    }
+
+    fn add_rect(&mut self, name: String, size: Size, tag: Option<Value>) -> Option<()> {
+        let mut orientations = vec![(size, false)];
+        if self.allow_rotation && size.width != size.height {
+            orientations.push((size.rotated(), true));
         }
+
+        let (index, size, rotated) = orientations
+            .into_iter()
+            .filter_map(|(size, rotated)| {
+                let (index, score) = self.best_free_rect(size)?;
+                Some((index, size, rotated, score))
+            })
+            .min_by_key(|&(_, _, _, score)| score)
+            .map(|(index, size, rotated, _)| (index, size, rotated))?;
+
+        let chosen = self.free_rects[index].clone();
+        let placed = Rect {
+            left: chosen.left,
+            top: chosen.top,
+            size,
+            rotated,
+            tag,
+            ..Default::default()
+        };
+
+        if let Some(_old) = self.textures.insert(name.clone(), placed.clone()) {
+            warn!("Image {:?} inserted multiple times", &name);
+        }
+        self.split_free_rects(&placed);
+        self.prune_free_rects();
         Some(())
     }
 
-    fn add_rect(&mut self, name: String, mut size: Size) -> Option<()> {
-        for space in self.empty_spaces.iter_mut().rev() {
-            if let Some((texture_rect, new_empty)) = space.insert(size) {
-                if let Some(_old) = self.textures.insert(name.clone(), texture_rect){
-                    warn!("Image {:?} inserted multiple times", &name);
-                }
-                if let Some(space) = new_empty {
-                    self.empty_spaces.push(space);
+    /// Replace every free rect intersecting `placed` with up to four slices
+    /// (above, below, left, right) that are still free.
+    fn split_free_rects(&mut self, placed: &Rect) {
+        let mut i = 0;
+        let mut new_free = Vec::new();
+        while i < self.free_rects.len() {
+            if self.free_rects[i].intersects(placed) {
+                let free = self.free_rects.swap_remove(i);
+                new_free.extend(Self::slice(&free, placed));
+            } else {
+                i += 1;
+            }
+        }
+        self.free_rects.extend(new_free);
+    }
+
+    /// Slice `free` around the overlapping `placed` rect, producing the
+    /// (possibly empty) above/below/left/right remainders clipped to `free`.
+    fn slice(free: &Rect, placed: &Rect) -> Vec<Rect> {
+        let mut slices = Vec::with_capacity(4);
+        if placed.left < free.right() && placed.right() > free.left {
+            if placed.top > free.top && placed.top < free.bottom() {
+                slices.push(Rect {
+                    left: free.left,
+                    top: free.top,
+                    size: Size {
+                        width: free.size.width,
+                        height: placed.top - free.top,
+                    },
+                    ..Default::default()
+                });
+            }
+            if placed.bottom() < free.bottom() {
+                slices.push(Rect {
+                    left: free.left,
+                    top: placed.bottom(),
+                    size: Size {
+                        width: free.size.width,
+                        height: free.bottom() - placed.bottom(),
+                    },
+                    ..Default::default()
+                });
+            }
+        }
+        if placed.top < free.bottom() && placed.bottom() > free.top {
+            if placed.left > free.left && placed.left < free.right() {
+                slices.push(Rect {
+                    left: free.left,
+                    top: free.top,
+                    size: Size {
+                        width: placed.left - free.left,
+                        height: free.size.height,
+                    },
+                    ..Default::default()
+                });
+            }
+            if placed.right() < free.right() {
+                slices.push(Rect {
+                    left: placed.right(),
+                    top: free.top,
+                    size: Size {
+                        width: free.right() - placed.right(),
+                        height: free.size.height,
+                    },
+                    ..Default::default()
+                });
+            }
+        }
+        slices
+    }
+
+    /// Drop any free rect that is fully contained inside another free rect,
+    /// since it can never be a better placement candidate than its container.
+    fn prune_free_rects(&mut self) {
+        let mut i = 0;
+        'outer: while i < self.free_rects.len() {
+            for j in 0..self.free_rects.len() {
+                if i != j && self.free_rects[j].contains(&self.free_rects[i]) {
+                    self.free_rects.swap_remove(i);
+                    continue 'outer;
                 }
-                return Some(());
             }
+            i += 1;
         }
-        None
     }
 
     pub fn min_bounding_rect(&self) -> Size {
@@ -161,3 +401,61 @@ impl AtlasBuilder {
             .fold(Size::zero(), Size::max)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(width: u32, height: u32) -> Size {
+        Size { width, height }
+    }
+
+    #[test]
+    fn slice_carves_four_remainders_around_an_interior_placement() {
+        let free = Rect { left: 0, top: 0, size: size(100, 100), ..Default::default() };
+        let placed = Rect { left: 20, top: 30, size: size(10, 10), ..Default::default() };
+
+        let slices = AtlasBuilder::slice(&free, &placed);
+
+        assert_eq!(slices.len(), 4);
+        for slice in &slices {
+            assert!(free.contains(slice));
+            assert!(!slice.intersects(&placed));
+        }
+    }
+
+    #[test]
+    fn prune_free_rects_drops_rects_fully_contained_in_another() {
+        let mut builder = AtlasBuilder::new(100, 100);
+        builder.free_rects = vec![
+            Rect { left: 0, top: 0, size: size(100, 100), ..Default::default() },
+            Rect { left: 10, top: 10, size: size(20, 20), ..Default::default() },
+        ];
+
+        builder.prune_free_rects();
+
+        assert_eq!(builder.free_rects.len(), 1);
+        assert_eq!(builder.free_rects[0].size, size(100, 100));
+    }
+
+    #[test]
+    fn rotated_orientation_is_used_when_it_fits_better() {
+        let mut builder = AtlasBuilder::new(50, 30).allow_rotation(true);
+
+        let report = builder.build(vec![("tall".to_string(), size(20, 40), None)]);
+
+        assert!(report.overflow.is_empty());
+        let rect = &builder.textures["tall"];
+        assert!(rect.rotated);
+        assert_eq!(rect.size, size(40, 20));
+    }
+
+    #[test]
+    fn used_space_subtracts_border_to_report_content_occupancy() {
+        let mut builder = AtlasBuilder::new(100, 100).border(2);
+
+        builder.build(vec![("a".to_string(), size(12, 22), None)]);
+
+        assert_eq!(builder.used_space().used_area, 10 * 20);
+    }
+}
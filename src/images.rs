@@ -4,6 +4,7 @@ use image;
 use image::{GenericImageView, GenericImage, RgbaImage, DynamicImage, Pixel};
 use image::ImageResult;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::path::PathBuf;
 use std::collections::HashMap;
 
@@ -12,6 +13,10 @@ pub struct ImageDefinition {
     path: PathBuf,
     #[serde(default)]
     repeat: bool,
+    /// Arbitrary caller metadata (sprite name, animation frame index,
+    /// nine-slice border, ...) echoed back into the matching map entry.
+    #[serde(default)]
+    tag: Option<Value>,
 }
 
 impl ImageDefinition {
@@ -31,6 +36,30 @@ pub struct Config {
     pub output_image: PathBuf,
     pub output_map: PathBuf,
     pub border: u32,
+    pub allow_rotation: bool,
+    pub max_pages: u32,
+    pub color_encoding: ColorEncoding,
+}
+
+/// How source pixels are stored into the output atlas texture.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorEncoding {
+    /// Source bytes are copied through unchanged.
+    #[default]
+    Srgb,
+    /// RGB is converted from sRGB to linear and premultiplied by alpha before
+    /// storage, which avoids the dark fringing naive sRGB premultiplication
+    /// produces around transparent sprite edges under filtering.
+    LinearPremultiplied,
+}
+
+/// Combined map covering every output page, serialized to `output_map`.
+#[derive(Debug, Serialize)]
+struct AtlasMap {
+    textures: HashMap<String, Rect>,
+    pages: Vec<Size>,
+    color_encoding: ColorEncoding,
 }
 
 pub fn build(config: &Config) -> ImageResult<()> {
@@ -48,37 +77,231 @@ pub fn build(config: &Config) -> ImageResult<()> {
         };
         let name = def.path.clone().into_os_string().to_string_lossy().to_string();
         images.insert(name.clone(), image);
-        rects.push((name, size));
+        rects.push((name, size, def.tag.clone()));
     }
-    let mut builder = AtlasBuilder::new(config.width, config.height);
-    builder.build(rects).expect("Could not fit images into atlas of specified size");
-    let bound_size = builder.min_bounding_rect();
-    let map = builder.get_map();
-
-    let mut buffer = RgbaImage::new(bound_size.width, bound_size.height);
-    for (name, image) in &images{
-        let mut rect = *map.textures.get(name).unwrap_or_else(|| panic!("Image {:?} has no associated space!", name));
-        rect.size.width -= config.border;
-        rect.size.height -= config.border;
-        copy_to_rgba(image, &mut buffer, rect);
+
+    let repeat: HashMap<String, bool> = config.input.iter()
+        .map(|def| {
+            let name = def.path.clone().into_os_string().to_string_lossy().to_string();
+            (name, def.repeat)
+        })
+        .collect();
+
+    let mut textures = HashMap::with_capacity(rects.len());
+    let mut pages = Vec::new();
+    let mut pending = rects;
+    let mut page: u32 = 0;
+    while !pending.is_empty() {
+        let mut builder = AtlasBuilder::new(config.width, config.height)
+            .allow_rotation(config.allow_rotation)
+            .border(config.border);
+        let report = builder.build(pending);
+        let bound_size = builder.min_bounding_rect();
+        let map = builder.get_map();
+
+        info!(
+            "Page {}: {:.1}% occupancy ({} / {} px^2 used)",
+            page,
+            report.used_space.occupancy() * 100.0,
+            report.used_space.used_area,
+            report.used_space.total_area,
+        );
+
+        if map.textures.is_empty() {
+            let failure = report.failures.first().expect("non-empty pending implies at least one failure");
+            return Err(image::ImageError::FormatError(format!(
+                "Could not fit image {:?} ({}x{}) into atlas of {}x{}: largest remaining free rect is {:?}. \
+                 page {} placed nothing, so no further page would help",
+                failure.name, failure.size.width, failure.size.height,
+                config.width, config.height, failure.largest_free_rect, page,
+            )));
+        }
+
+        if !report.overflow.is_empty() && page + 1 >= config.max_pages {
+            let failure = report.failures.first().expect("overflow implies at least one failure");
+            return Err(image::ImageError::FormatError(format!(
+                "Could not fit image {:?} ({}x{}) into atlas of {}x{}: largest remaining free rect is {:?}. \
+                 max_pages ({}) reached with {} image(s) left over",
+                failure.name, failure.size.width, failure.size.height,
+                config.width, config.height, failure.largest_free_rect,
+                config.max_pages, report.overflow.len(),
+            )));
+        }
+
+        let mut buffer = RgbaImage::new(bound_size.width, bound_size.height);
+        for (name, rect) in &map.textures {
+            let image = &images[name];
+            let mut content = rect.clone();
+            content.size.width -= config.border;
+            content.size.height -= config.border;
+            copy_to_rgba(image, &mut buffer, content.clone(), config.color_encoding);
+            extrude_border(&mut buffer, &content, config.border, repeat[name]);
+        }
+        buffer.save(page_output_path(&config.output_image, page))?;
+
+        for (name, mut rect) in map.textures {
+            rect.page = page;
+            // UVs (and the serialized size) describe the sprite content, not
+            // the border-padded footprint used for packing and extrusion.
+            rect.size.width -= config.border;
+            rect.size.height -= config.border;
+            rect.set_uv(map.size);
+            textures.insert(name, rect);
+        }
+        pages.push(map.size);
+        pending = report.overflow;
+        page += 1;
     }
-    buffer.save(config.output_image.clone())?;
 
+    let map = AtlasMap { textures, pages, color_encoding: config.color_encoding };
     let mut out = File::create(&config.output_map)?;
     serde_json::to_writer_pretty(&mut out, &map).unwrap();
     Ok(())
 }
 
-fn copy_to_rgba(from: &DynamicImage, into: &mut RgbaImage, rect: Rect){
+/// Derive `{stem}_{page}.{ext}` (e.g. `out_texture_0.png` for
+/// `out_texture.png`) from the configured output path.
+fn page_output_path(base: &std::path::Path, page: u32) -> PathBuf {
+    let stem = base.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let file_name = match base.extension() {
+        Some(ext) => format!("{}_{}.{}", stem, page, ext.to_string_lossy()),
+        None => format!("{}_{}", stem, page),
+    };
+    base.with_file_name(file_name)
+}
+
+/// Fill the `border`-pixel band to the right of and below `content` (the
+/// padding baked into every rect's footprint, see `Config::border`) so
+/// bilinear filtering and mipmapping sample extruded edge color instead of
+/// bleeding into whatever was packed next to it. With `repeat`, the opposite
+/// edge is wrapped in instead of the adjacent one, so the region tiles under
+/// GPU repeat sampling.
+fn extrude_border(buffer: &mut RgbaImage, content: &Rect, border: u32, repeat: bool) {
+    let left = content.left;
+    let top = content.top;
+    let width = content.size.width;
+    let height = content.size.height;
+
+    let right_source_x = if repeat { left } else { left + width - 1 };
+    for dx in 0..border {
+        let x = left + width + dx;
+        if x >= buffer.width() {
+            break;
+        }
+        for y in top..top + height {
+            let pixel = *buffer.get_pixel(right_source_x, y);
+            buffer.put_pixel(x, y, pixel);
+        }
+    }
+
+    let bottom_source_y = if repeat { top } else { top + height - 1 };
+    for dy in 0..border {
+        let y = top + height + dy;
+        if y >= buffer.height() {
+            break;
+        }
+        for x in left..left + width {
+            let pixel = *buffer.get_pixel(x, bottom_source_y);
+            buffer.put_pixel(x, y, pixel);
+        }
+    }
+
+    let corner_pixel = *buffer.get_pixel(right_source_x, bottom_source_y);
+    for dx in 0..border {
+        let x = left + width + dx;
+        if x >= buffer.width() {
+            break;
+        }
+        for dy in 0..border {
+            let y = top + height + dy;
+            if y >= buffer.height() {
+                break;
+            }
+            buffer.put_pixel(x, y, corner_pixel);
+        }
+    }
+}
+
+fn copy_to_rgba(from: &DynamicImage, into: &mut RgbaImage, rect: Rect, color_encoding: ColorEncoding){
     assert!(rect.left + rect.size.width <= into.width());
     assert!(rect.top + rect.size.height <= into.height());
-    assert_eq!(rect.size.width, from.width());
-    assert_eq!(rect.size.height, from.height());
+    if rect.rotated {
+        assert_eq!(rect.size.width, from.height());
+        assert_eq!(rect.size.height, from.width());
+    } else {
+        assert_eq!(rect.size.width, from.width());
+        assert_eq!(rect.size.height, from.height());
+    }
 
     for y in 0..rect.size.height{
         for x in 0..rect.size.width{
-            let pixel = from.get_pixel(x, y);
+            let pixel = if rect.rotated {
+                // Clockwise 90°: dest (x, y) <- src (y, from.height() - 1 - x).
+                from.get_pixel(y, from.height() - 1 - x)
+            } else {
+                from.get_pixel(x, y)
+            };
+            let pixel = apply_color_pipeline(pixel, color_encoding);
             into.put_pixel(rect.left + x, rect.top + y, pixel);
         }
     }
 }
+
+/// Convert a source pixel into the atlas's storage encoding.
+fn apply_color_pipeline(pixel: image::Rgba<u8>, color_encoding: ColorEncoding) -> image::Rgba<u8> {
+    match color_encoding {
+        ColorEncoding::Srgb => pixel,
+        ColorEncoding::LinearPremultiplied => {
+            let image::Rgba([r, g, b, a]) = pixel;
+            let alpha = a as f32 / 255.0;
+            let premultiply = |c: u8| {
+                let linear = srgb_to_linear(c as f32 / 255.0);
+                (linear * alpha * 255.0).round().clamp(0.0, 255.0) as u8
+            };
+            image::Rgba([premultiply(r), premultiply(g), premultiply(b), a])
+        }
+    }
+}
+
+/// Standard sRGB electro-optical transfer function, `c` in `[0, 1]`.
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotated_blit_performs_a_true_90_degree_rotation() {
+        let mut src = RgbaImage::new(2, 3);
+        for y in 0..3 {
+            for x in 0..2 {
+                src.put_pixel(x, y, image::Rgba([x as u8, y as u8, 0, 255]));
+            }
+        }
+        let from = DynamicImage::ImageRgba8(src);
+        let rect = Rect {
+            left: 0,
+            top: 0,
+            size: Size { width: 3, height: 2 },
+            rotated: true,
+            ..Default::default()
+        };
+        let mut into = RgbaImage::new(3, 2);
+
+        copy_to_rgba(&from, &mut into, rect, ColorEncoding::Srgb);
+
+        // Clockwise 90°: dest(x, y) <- src(y, from.height() - 1 - x).
+        for y in 0..2u32 {
+            for x in 0..3u32 {
+                let expected = from.get_pixel(y, 2 - x);
+                assert_eq!(*into.get_pixel(x, y), expected);
+            }
+        }
+    }
+}
@@ -12,13 +12,23 @@ use std::fs::File;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
-use images::{Config, build};
+use images::{Config, ColorEncoding, build};
 
 #[derive(Deserialize, Debug)]
 struct TextureList {
     images: Vec<ImageDefinition>,
     width: u32,
     height: u32,
+    #[serde(default)]
+    allow_rotation: bool,
+    #[serde(default = "default_max_pages")]
+    max_pages: u32,
+    #[serde(default)]
+    linear_premultiplied: bool,
+}
+
+fn default_max_pages() -> u32 {
+    1
 }
 
 #[derive(Deserialize, Debug)]
@@ -72,6 +82,13 @@ fn main() -> ImageResult<()> {
         output_image: ops.out_texture,
         output_map: ops.out_map,
         border: 1,
+        allow_rotation: textures.allow_rotation,
+        max_pages: textures.max_pages,
+        color_encoding: if textures.linear_premultiplied {
+            ColorEncoding::LinearPremultiplied
+        } else {
+            ColorEncoding::Srgb
+        },
     };
 
     build(&config)?;